@@ -1,22 +1,69 @@
 use std::io;
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 mod tcp;
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
-struct Quad {
-	src: (Ipv4Addr, u16), // (<ip_addr>, <port>)
-	dst: (Ipv4Addr, u16),
-}
+use tcp::Quad;
 
+/// Default poll timeout used while no connection has an outstanding
+/// retransmission timer (e.g. right after startup).
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> io::Result<()> {
 	let mut connections: HashMap<Quad, tcp::Connection> = Default::default();
-	let mut nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
-	let mut buf = [0u8; 1504];
+	let nic = Arc::new(Mutex::new(tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?));
+
+	// `tun_tap::Iface` has no notion of a read timeout, but we need to wake
+	// up on a schedule to drive each connection's retransmission timer. So a
+	// background thread just blocks on `recv` and forwards raw frames over a
+	// channel, and the main loop waits on that channel with a timeout
+	// computed from the connections' RTOs instead.
+	let (tx, rx) = mpsc::channel::<Vec<u8>>();
+	{
+		let nic = Arc::clone(&nic);
+		thread::spawn(move || {
+			let mut buf = [0u8; 1504];
+			loop {
+				let nbytes = match nic.lock().unwrap().recv(&mut buf[..]) {
+					Ok(n) => n,
+					Err(_) => break,
+				};
+				if tx.send(buf[..nbytes].to_vec()).is_err() {
+					break;
+				}
+			}
+		});
+	}
+
 	loop {
-		let nbytes = nic.recv(&mut buf[..])?;
+		let timeout = connections
+			.values()
+			.filter_map(|c| c.next_tick_in())
+			.min()
+			.unwrap_or(DEFAULT_POLL_TIMEOUT);
+
+		let buf = match rx.recv_timeout(timeout) {
+			Ok(buf) => buf,
+			Err(mpsc::RecvTimeoutError::Timeout) => {
+				let mut nic = nic.lock().unwrap();
+				let mut done = Vec::new();
+				for (&quad, c) in connections.iter_mut() {
+					if c.on_tick(&mut nic)? {
+						done.push(quad);
+					}
+				}
+				for quad in done {
+					connections.remove(&quad);
+				}
+				continue;
+			}
+			Err(mpsc::RecvTimeoutError::Disconnected) => break,
+		};
+		let nbytes = buf.len();
 		// let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
 		// let eth_protocol = u16::from_be_bytes([buf[2], buf[3]]);
 		// if eth_protocol != 0x0800 { //ignore packets that are not ipv4
@@ -32,24 +79,28 @@ fn main() -> io::Result<()> {
 					// ignore packets that are not TCP
 					continue;
 				}
- 
+
 				match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..]) {
 					Ok(tcph) => {
 						use std::collections::hash_map::Entry;
 						let datai = iph.slice().len() + tcph.slice().len();
+						let mut nic = nic.lock().unwrap();
 						match connections.entry(Quad{
 							src: (src, tcph.source_port()),
 							dst: (dst, tcph.destination_port()),
 						}) {
 							Entry::Occupied(mut c) => {
-								c.get_mut()
+								let done = c.get_mut()
 									.on_packet(&mut nic, iph, tcph, &buf[datai..nbytes])?;
+								if done {
+									c.remove();
+								}
 							}
 							Entry::Vacant(mut e) => {
 								if let Some(c) = tcp::Connection::accept(
 									&mut nic,
-									iph, 
-									tcph, 
+									iph,
+									tcph,
 									&buf[datai..nbytes]
 								)? {
 									e.insert(c);
@@ -67,4 +118,5 @@ fn main() -> io::Result<()> {
 			}
 		}
 	}
+	Ok(())
 }
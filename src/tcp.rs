@@ -1,19 +1,140 @@
 use std::io;
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::OnceLock;
+use std::time;
+
+/// Lower and upper bounds applied to the retransmission timeout (RFC 6298 S2).
+const RTO_MIN: time::Duration = time::Duration::from_secs(1);
+const RTO_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// MSS assumed until the peer's MSS option tells us otherwise.
+const DEFAULT_MSS: u32 = 536;
+/// MSS we advertise in our own MSS option.
+const OUR_MSS: u16 = 1460;
+/// Window scale shift (RFC 1323) we offer in our own window-scale option;
+/// only honored if the peer offers window scaling too.
+const OUR_WND_SCALE: u8 = 5;
+/// RFC 1323 S2.2 caps the window scale shift at 14; a larger value on the
+/// wire would overflow the `u32 << shift` math used to apply it.
+const MAX_WND_SCALE: u8 = 14;
+/// Our own advertised receive window, in real bytes.
+const OUR_RECV_WND: u32 = 1 << 20;
+
+/// Maximum Segment Lifetime (RFC 793 S3.3), used to size the TIME-WAIT timer.
+const MSL: time::Duration = time::Duration::from_secs(120);
+/// How long a connection sits in TIME-WAIT before we forget about it.
+const TIME_WAIT_DURATION: time::Duration = time::Duration::from_secs(MSL.as_secs() * 2);
+
+/// MSS and window-scale options parsed out of a SYN/SYN-ACK.
+#[derive(Default)]
+struct TcpOptions {
+    mss: Option<u16>,
+    wscale: Option<u8>,
+}
+
+/// Walk a TCP option list looking for the MSS and window-scale options,
+/// skipping anything else (RFC 793 S3.1, RFC 1323 S2.2).
+fn parse_options(mut opts: &[u8]) -> TcpOptions {
+    let mut parsed = TcpOptions::default();
+    while let Some(&kind) = opts.first() {
+        match kind {
+            0 => break, // end of option list
+            1 => opts = &opts[1..], // no-op padding
+            2 if opts.len() >= 4 => {
+                // an MSS of 0 is nonsensical (and would make the congestion
+                // window math divide by zero downstream) -- treat it the
+                // same as the option being absent
+                let mss = u16::from_be_bytes([opts[2], opts[3]]);
+                if mss != 0 {
+                    parsed.mss = Some(mss);
+                }
+                opts = &opts[4..];
+            }
+            3 if opts.len() >= 3 => {
+                parsed.wscale = Some(opts[2].min(MAX_WND_SCALE));
+                opts = &opts[3..];
+            }
+            _ => {
+                let len = (opts.get(1).copied().unwrap_or(0) as usize).max(2);
+                if len > opts.len() {
+                    break;
+                }
+                opts = &opts[len..];
+            }
+        }
+    }
+    parsed
+}
+
+/// A TCP connection is identified by its 4-tuple.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Quad {
+    pub src: (Ipv4Addr, u16),
+    pub dst: (Ipv4Addr, u16),
+}
+
+/// Hands out ephemeral local ports for `Connection::connect`, starting
+/// from the IANA-registered ephemeral range.
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(0);
+
+fn ephemeral_port() -> u16 {
+    const BASE: u16 = 49152;
+    const RANGE: u16 = u16::MAX - BASE;
+    BASE + (NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed) % RANGE)
+}
+
+/// Per-process secret mixed into every ISN below, so an off-path attacker
+/// can't predict one connection's ISN from another's (RFC 6528 S3).
+static ISN_SECRET: OnceLock<RandomState> = OnceLock::new();
+
+/// Generate an initial sequence number for a connection between `local` and
+/// `remote`: a clock that advances once every 4 microseconds (the classic
+/// ISN clock rate) plus a keyed hash of the 4-tuple, so connections opened
+/// at the same instant still land on unrelated ISNs (RFC 6528).
+fn generate_iss(local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> u32 {
+    let secret = ISN_SECRET.get_or_init(RandomState::new);
+    let mut hasher = secret.build_hasher();
+    local.hash(&mut hasher);
+    remote.hash(&mut hasher);
+    let quad_hash = hasher.finish() as u32;
+
+    let micros = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("system clock is set before the Unix epoch")
+        .as_micros();
+    let clock = (micros / 4) as u32;
+
+    clock.wrapping_add(quad_hash)
+}
 
 enum State {
-    // Listen,
+    Listen,
     SynRcvd,
+    SynSent,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
+    LastAck,
+    Closing,
     TimeWait,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::Listen | State::SynRcvd | State::SynSent => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::LastAck
+            | State::Closing
+            | State::TimeWait => true,
         }
     }
 }
@@ -24,6 +145,220 @@ pub struct Connection {
     recv: RecvSequenceSpace,
     ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
+    /// Segments we've sent that haven't been acked yet, oldest first.
+    unacked: VecDeque<Unacked>,
+    timers: Timers,
+    /// Sequence number of the next byte `recv()` will hand to the app --
+    /// i.e. the sequence number of `incoming[0]`.
+    read_seq: u32,
+    /// Bytes we've received, starting at `read_seq`. Bytes at offsets below
+    /// `recv.nxt - read_seq` are in-order and ready for `recv()`; bytes past
+    /// that point are held here ahead of time because they arrived out of
+    /// order, and become readable once the gap before them fills in.
+    incoming: VecDeque<u8>,
+    /// Tracks which byte ranges of the receive window we already have data
+    /// for, so we know when a new contiguous prefix has formed.
+    assembler: Assembler,
+    cong: Congestion,
+    /// Shift applied to the window field on incoming segments to get
+    /// `send.wnd`. Zero unless both sides offered window scaling.
+    send_wnd_scale: u8,
+    /// Shift applied to `recv.wnd` to get the window field we advertise.
+    /// Zero unless both sides offered window scaling.
+    recv_wnd_scale: u8,
+    /// Set once the peer's FIN has been folded into `recv.nxt`, i.e. there's
+    /// no more data coming. Checked by `recv()` callers to detect EOF.
+    eof: bool,
+    /// Armed when entering `TimeWait`; once it passes, `on_tick` tells `main`
+    /// to drop this connection from its table (RFC 793 S3.5).
+    time_wait_deadline: Option<time::Instant>,
+    /// Sequence number of our own FIN, once we've sent one. Lets the
+    /// closing-state checks tell whether our FIN was ACK'd without assuming
+    /// anything about `send.iss`.
+    fin_seq: Option<u32>,
+}
+
+/// Tracks non-overlapping, non-touching `(start, end)` ranges (in absolute
+/// sequence-number space) that we've received data for but that may not yet
+/// be contiguous with `recv.nxt`.
+#[derive(Default)]
+struct Assembler {
+    received: Vec<(u32, u32)>,
+}
+
+impl Assembler {
+    /// Record that bytes `[start, end)` have arrived, merging them with
+    /// whatever existing ranges they now touch or overlap.
+    fn insert(&mut self, mut start: u32, mut end: u32) {
+        if start == end {
+            return;
+        }
+        let mut merged = Vec::with_capacity(self.received.len() + 1);
+        for &(s, e) in &self.received {
+            if e < start || end < s {
+                // disjoint from the new range -- leave it alone
+                merged.push((s, e));
+            } else {
+                // touching or overlapping -- fold it into the new range
+                start = start.min(s);
+                end = end.max(e);
+            }
+        }
+        merged.push((start, end));
+        merged.sort_unstable_by_key(|&(s, _)| s);
+        self.received = merged;
+    }
+
+    /// If we have a range starting exactly at `from`, remove it and return
+    /// its end -- i.e. the next contiguous chunk ready to be delivered.
+    fn pop_contiguous(&mut self, from: u32) -> Option<u32> {
+        let idx = self.received.iter().position(|&(s, _)| s == from)?;
+        Some(self.received.remove(idx).1)
+    }
+}
+
+/// A segment we've sent that is still waiting on an ACK, kept around so
+/// `on_tick` can resend it if it times out.
+struct Unacked {
+    /// First sequence number occupied by this segment.
+    seq: u32,
+    /// Number of sequence numbers it occupies (payload len, plus SYN/FIN).
+    len: u32,
+    /// The payload bytes we sent, so we can resend exactly the same thing.
+    data: Vec<u8>,
+    /// TCP options this segment carried, so a retransmitted SYN/SYN-ACK
+    /// doesn't silently drop MSS/window-scale negotiation.
+    options: Vec<etherparse::TcpOptionElement>,
+    syn: bool,
+    fin: bool,
+    sent_at: time::Instant,
+    /// Set once this segment has been retransmitted. Per Karn's algorithm we
+    /// never take an RTT sample from a retransmitted segment, since we can't
+    /// tell which transmission the eventual ACK is acking.
+    retransmitted: bool,
+}
+
+/// Smoothed RTT estimation and RTO computation (RFC 6298).
+struct Timers {
+    srtt: Option<time::Duration>,
+    rttvar: time::Duration,
+    rto: time::Duration,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            srtt: None,
+            rttvar: time::Duration::from_millis(0),
+            rto: RTO_MIN,
+        }
+    }
+}
+
+impl Timers {
+    /// Record an RTT sample `r` and recompute `rto` from it (RFC 6298 S2.3).
+    fn sample(&mut self, r: time::Duration) {
+        self.rttvar = match self.srtt {
+            Some(srtt) => {
+                let diff = if srtt > r { srtt - r } else { r - srtt };
+                self.rttvar * 3 / 4 + diff / 4
+            }
+            None => r / 2,
+        };
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => srtt * 7 / 8 + r / 8,
+            None => r,
+        });
+        self.rto = (self.srtt.unwrap() + self.rttvar * 4).clamp(RTO_MIN, RTO_MAX);
+    }
+
+    /// Exponential backoff applied on each retransmission, per Karn's
+    /// algorithm, until a non-retransmitted segment is acked and `sample`
+    /// replaces this with a fresh estimate.
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(RTO_MAX);
+    }
+}
+
+/// NewReno congestion control: slow start, congestion avoidance, and fast
+/// retransmit / fast recovery (RFC 5681, RFC 6582).
+struct Congestion {
+    cwnd: u32,
+    ssthresh: u32,
+    /// MSS used for the window-growth and initial-window math below; gets
+    /// updated once we've parsed the peer's MSS option.
+    mss: u32,
+    /// Consecutive duplicate ACKs seen since the last new ACK.
+    dup_acks: u32,
+    /// Set to `send.nxt` the moment fast retransmit kicks in; we stay in
+    /// fast recovery until an ACK at or past this point arrives (NewReno).
+    recover: Option<u32>,
+}
+
+impl Congestion {
+    fn new(mss: u32) -> Self {
+        // an MSS of 0 would make the window-growth math divide by zero
+        let mss = mss.max(1);
+        Congestion {
+            cwnd: 3 * mss,
+            ssthresh: u32::MAX,
+            mss,
+            dup_acks: 0,
+            recover: None,
+        }
+    }
+
+    /// Bytes we're currently allowed to have in flight.
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    /// A new ACK arrived, advancing `send.una`.
+    fn on_new_ack(&mut self) {
+        self.dup_acks = 0;
+        if self.cwnd < self.ssthresh {
+            // slow start: +1 MSS per ACK
+            self.cwnd += self.mss;
+        } else {
+            // congestion avoidance: +~1 MSS per RTT
+            self.cwnd += (self.mss * self.mss / self.cwnd).max(1);
+        }
+    }
+
+    /// A new ACK arrived while we were in fast recovery and it covers the
+    /// recovery point: recovery is over, deflate back to `ssthresh`.
+    fn exit_recovery(&mut self) {
+        self.cwnd = self.ssthresh;
+        self.dup_acks = 0;
+        self.recover = None;
+    }
+
+    /// A duplicate ACK arrived (repeats `send.una`, acks no new data).
+    /// Returns `true` the moment the third one triggers fast retransmit.
+    fn on_duplicate_ack(&mut self, flightsize: u32) -> bool {
+        self.dup_acks += 1;
+        match self.dup_acks {
+            3 => {
+                self.ssthresh = (flightsize / 2).max(2 * self.mss);
+                self.cwnd = self.ssthresh + 3 * self.mss;
+                true
+            }
+            n if n > 3 => {
+                // fast recovery: inflate the window for each further dupack
+                self.cwnd += self.mss;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// A retransmission timeout fired.
+    fn on_rto(&mut self, flightsize: u32) {
+        self.ssthresh = (flightsize / 2).max(2 * self.mss);
+        self.cwnd = self.mss;
+        self.dup_acks = 0;
+        self.recover = None;
+    }
 }
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
@@ -46,14 +381,15 @@ struct SendSequenceSpace {
     una: u32,
     /// send next
     nxt: u32,
-    /// send window
-    wnd: u16,
+    /// send window, in real bytes (i.e. peer's advertised window already
+    /// shifted by `send_wnd_scale`)
+    wnd: u32,
     /// send urgent pointer
     up: bool, 
     /// segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     /// segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     /// initial send sequence number
     iss: u32, 
 
@@ -77,8 +413,8 @@ struct SendSequenceSpace {
 struct RecvSequenceSpace {
     /// receive next
     nxt: u32,
-    /// receive window
-    wnd: u16,
+    /// receive window, in real bytes
+    wnd: u32,
     /// receive urgent pointer
     up: bool,
     /// initial receive sequence number
@@ -100,71 +436,165 @@ impl Connection {
         }
         
 
+        let opts = parse_options(tcph.options());
+        let peer_mss = opts.mss.map(u32::from).unwrap_or(DEFAULT_MSS);
+        // window scaling is only used if *both* sides offer it
+        let send_wnd_scale = opts.wscale.unwrap_or(0);
+        let recv_wnd_scale = if opts.wscale.is_some() { OUR_WND_SCALE } else { 0 };
+        let advertised_wnd = (OUR_RECV_WND >> recv_wnd_scale).min(u16::MAX as u32) as u16;
+
         // Creating a new connection as SYN was recv'd
-        let iss = 0;
-        let wnd = 10;
+        let iss = generate_iss(
+            (iph.destination_addr(), tcph.destination_port()),
+            (iph.source_addr(), tcph.source_port()),
+        );
         let mut c = Connection {
-            state: State::SynRcvd,
+            // we were sitting in LISTEN until this SYN showed up
+            state: State::Listen,
             send: SendSequenceSpace {
                 iss,
                 una: iss, // last thing we sent that is not ack'd by client
                 nxt: iss,
-                wnd: wnd,
+                // RFC 1323 S2.3: the window field on the segment that
+                // carries the Window Scale option itself is unscaled --
+                // scaling only applies from the next segment on
+                wnd: tcph.window_size() as u32,
                 up: false,
-                wl1: 0,
+                wl1: tcph.sequence_number(),
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
                 irs: tcph.sequence_number(),
                 nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
+                wnd: OUR_RECV_WND,
                 up: false,
             },
             tcp: etherparse::TcpHeader::new(
-                tcph.destination_port(), 
-                tcph.source_port(), 
-                iss,  // 0 for now, truly random ISN implementation later
-                wnd,
+                tcph.destination_port(),
+                tcph.source_port(),
+                iss,
+                advertised_wnd,
             ),
             ip: etherparse::Ipv4Header::new(
-                0,                
-                64, 
-                etherparse::IpTrafficClass::Tcp, 
+                0,
+                64,
+                etherparse::IpTrafficClass::Tcp,
                 [
-                    iph.destination()[0], 
-                    iph.destination()[1], 
-                    iph.destination()[2], 
+                    iph.destination()[0],
+                    iph.destination()[1],
+                    iph.destination()[2],
                     iph.destination()[3],
-                ], 
+                ],
                 [
                     iph.source()[0],
                     iph.source()[1],
                     iph.source()[2],
                     iph.source()[3],
-                ], 
+                ],
             ),
+            unacked: Default::default(),
+            timers: Default::default(),
+            read_seq: tcph.sequence_number().wrapping_add(1),
+            incoming: Default::default(),
+            assembler: Default::default(),
+            cong: Congestion::new(peer_mss.min(OUR_MSS as u32)),
+            send_wnd_scale,
+            recv_wnd_scale,
+            eof: false,
+            time_wait_deadline: None,
+            fin_seq: None,
         };
 
+        let mut syn_ack_opts = vec![etherparse::TcpOptionElement::MaximumSegmentSize(OUR_MSS)];
+        if opts.wscale.is_some() {
+            syn_ack_opts.push(etherparse::TcpOptionElement::WindowScale(OUR_WND_SCALE));
+        }
 
         // start establishing a connection
 
         c.tcp.syn = true;
         c.tcp.ack = true;
-        c.write(nic, &[])?;
+        c.write(nic, &[], syn_ack_opts)?;
+        c.state = State::SynRcvd;
         Ok(Some(c))
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
+    /// Actively open a connection to `quad.dst`, sending a bare SYN and
+    /// entering SYN-SENT. If `quad.src.1` is `0`, an ephemeral local port is
+    /// picked for the caller.
+    pub fn connect(nic: &mut tun_tap::Iface, mut quad: Quad) -> io::Result<Self> {
+        if quad.src.1 == 0 {
+            quad.src.1 = ephemeral_port();
+        }
+
+        let iss = generate_iss(quad.src, quad.dst);
+        // we don't know yet whether the peer supports window scaling, so
+        // advertise an unscaled window until the SYN-ACK tells us
+        let advertised_wnd = OUR_RECV_WND.min(u16::MAX as u32) as u16;
+        let mut c = Connection {
+            state: State::SynSent,
+            send: SendSequenceSpace {
+                iss,
+                una: iss,
+                nxt: iss,
+                wnd: 0,
+                up: false,
+                wl1: 0,
+                wl2: 0,
+            },
+            recv: RecvSequenceSpace {
+                // nothing received yet -- filled in once the SYN-ACK arrives
+                irs: 0,
+                nxt: 0,
+                wnd: OUR_RECV_WND,
+                up: false,
+            },
+            tcp: etherparse::TcpHeader::new(quad.src.1, quad.dst.1, iss, advertised_wnd),
+            ip: etherparse::Ipv4Header::new(
+                0,
+                64,
+                etherparse::IpTrafficClass::Tcp,
+                quad.src.0.octets(),
+                quad.dst.0.octets(),
+            ),
+            unacked: Default::default(),
+            timers: Default::default(),
+            read_seq: 0,
+            incoming: Default::default(),
+            assembler: Default::default(),
+            cong: Congestion::new(DEFAULT_MSS),
+            send_wnd_scale: 0,
+            recv_wnd_scale: 0,
+            eof: false,
+            time_wait_deadline: None,
+            fin_seq: None,
+        };
+
+        let opts = vec![
+            etherparse::TcpOptionElement::MaximumSegmentSize(OUR_MSS),
+            etherparse::TcpOptionElement::WindowScale(OUR_WND_SCALE),
+        ];
+
+        c.tcp.syn = true;
+        c.write(nic, &[], opts)?;
+        Ok(c)
+    }
+
+    /// Write `payload` to the wire at sequence number `seq`, using whatever
+    /// flags are currently set on `self.tcp`. Doesn't touch `send.nxt` or the
+    /// retransmission queue -- callers decide what that means for them, so
+    /// this is shared between fresh sends (`write`) and retransmits.
+    fn send_segment(&mut self, nic: &mut tun_tap::Iface, seq: u32, payload: &[u8]) -> io::Result<usize> {
         let mut buf = [0u8; 1500];
-        self.tcp.sequence_number = self.send.nxt;
+        self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
 
         let size = std::cmp::min(buf.len(), self.tcp.header_len() as usize + self.ip.header_len() as usize + payload.len(),);
         self.ip.set_payload_len(size - self.ip.header_len() as usize);
         self.tcp.checksum = self.tcp
          .calc_checksum_ipv4(&self.ip, &[])
-         .expect("failed to compute checksum"); 
-        
+         .expect("failed to compute checksum");
+
         // write headers into buffer
         use std::io::Write;
         let mut unwritten = &mut buf[..];
@@ -172,19 +602,258 @@ impl Connection {
         self.tcp.write(&mut unwritten);
         let payload_bytes = unwritten.write(payload)?;
         let unwritten = unwritten.len();
+        nic.send(&buf[..buf.len() - unwritten])?;
+        Ok(payload_bytes)
+    }
+
+    fn write(
+        &mut self,
+        nic: &mut tun_tap::Iface,
+        payload: &[u8],
+        options: Vec<etherparse::TcpOptionElement>,
+    ) -> io::Result<usize> {
+        let seq = self.send.nxt;
+        let syn = self.tcp.syn;
+        let fin = self.tcp.fin;
+
+        self.tcp.set_options(&options).expect("options fit in a TCP header");
+        let payload_bytes = self.send_segment(nic, seq, payload)?;
+        self.tcp.set_options(&[]).expect("clearing options always fits");
+
         self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
-        if self.tcp.syn {
+        if syn {
             self.send.nxt = self.send.nxt.wrapping_add(1);
             self.tcp.syn = false;
         }
-        if self.tcp.fin {
+        if fin {
+            // remember our FIN's own sequence number so the closing-state
+            // checks can tell once it's been ACK'd, without assuming
+            // anything about how much data preceded it
+            self.fin_seq = Some(self.send.nxt);
             self.send.nxt = self.send.nxt.wrapping_add(1);
             self.tcp.fin = false;
         }
-        nic.send(&buf[..buf.len() - unwritten])?;
+
+        // anything that occupied sequence space needs to be stored in the
+        // retransmission queue in case it gets lost
+        let len = payload_bytes as u32 + syn as u32 + fin as u32;
+        if len > 0 {
+            self.unacked.push_back(Unacked {
+                seq,
+                len,
+                data: payload[..payload_bytes].to_vec(),
+                options,
+                syn,
+                fin,
+                sent_at: time::Instant::now(),
+                retransmitted: false,
+            });
+        }
         Ok(payload_bytes)
     }
 
+    /// Total bytes currently outstanding (sent but not yet acked).
+    fn flightsize(&self) -> u32 {
+        self.unacked.iter().map(|seg| seg.len).sum()
+    }
+
+    /// How many more bytes of new data we're allowed to send right now --
+    /// bounded by both the peer's advertised window and our congestion
+    /// window, minus whatever's already in flight. Consulted by `send`
+    /// before it pushes more bytes onto the wire.
+    fn send_allowance(&self) -> u32 {
+        (self.cong.window().min(self.send.wnd)).saturating_sub(self.flightsize())
+    }
+
+    /// Resend the oldest unacked segment as-is, without touching the RTO.
+    /// Shared by the RTO path (which backs off on top of this) and fast
+    /// retransmit (which doesn't).
+    fn resend_oldest(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+        let mut seg = match self.unacked.pop_front() {
+            Some(seg) => seg,
+            None => return Ok(()),
+        };
+
+        let (saved_syn, saved_fin) = (self.tcp.syn, self.tcp.fin);
+        self.tcp.syn = seg.syn;
+        self.tcp.fin = seg.fin;
+        self.tcp.set_options(&seg.options).expect("previously-sent options still fit");
+        self.send_segment(nic, seg.seq, &seg.data)?;
+        self.tcp.set_options(&[]).expect("clearing options always fits");
+        self.tcp.syn = saved_syn;
+        self.tcp.fin = saved_fin;
+
+        seg.sent_at = time::Instant::now();
+        seg.retransmitted = true;
+        self.unacked.push_front(seg);
+        Ok(())
+    }
+
+    /// Resend the oldest unacked segment, doubling the RTO per Karn's
+    /// algorithm; its retransmission timer restarts from now.
+    fn retransmit(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+        self.resend_oldest(nic)?;
+        self.timers.backoff();
+        Ok(())
+    }
+
+    /// Called by `main`'s loop whenever its poll times out, so we can check
+    /// whether the oldest unacked segment needs retransmitting and whether
+    /// our TIME-WAIT timer has expired. Returns `true` once `main` should
+    /// drop this connection from its table.
+    pub fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<bool> {
+        if let Some(deadline) = self.time_wait_deadline {
+            return Ok(time::Instant::now() >= deadline);
+        }
+        if let Some(seg) = self.unacked.front() {
+            if seg.sent_at.elapsed() > self.timers.rto {
+                self.cong.on_rto(self.flightsize());
+                self.retransmit(nic)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// How long until the oldest unacked segment's RTO expires, or our
+    /// TIME-WAIT timer runs out, for `main` to use as its poll timeout.
+    /// `None` means we have nothing outstanding to wait on.
+    pub fn next_tick_in(&self) -> Option<time::Duration> {
+        if let Some(deadline) = self.time_wait_deadline {
+            return Some(deadline.saturating_duration_since(time::Instant::now()));
+        }
+        let seg = self.unacked.front()?;
+        let deadline = seg.sent_at + self.timers.rto;
+        Some(deadline.saturating_duration_since(time::Instant::now()))
+    }
+
+    /// Request that our side start closing (RFC 793 S3.5). From `Estab` this
+    /// is an active close (send FIN, move to `FinWait1`); from `CloseWait`
+    /// it's completing a passive close (send FIN, move to `LastAck`).
+    /// A no-op in any other state.
+    pub fn close(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+        match self.state {
+            State::Estab => {
+                self.tcp.fin = true;
+                self.write(nic, &[], vec![])?;
+                self.state = State::FinWait1;
+            }
+            State::CloseWait => {
+                self.tcp.fin = true;
+                self.write(nic, &[], vec![])?;
+                self.state = State::LastAck;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Queue as much of `buf` as `send_allowance` currently permits -- bounded
+    /// by the peer's advertised window, our congestion window, and the
+    /// peer's MSS, since a single segment can't exceed that. Returns the
+    /// number of bytes actually queued, which may be less than `buf.len()`;
+    /// the caller should retry the remainder once more room opens up.
+    /// `Ok(0)` outside `Estab`/`CloseWait`, where sending new data doesn't
+    /// make sense.
+    pub fn send(&mut self, nic: &mut tun_tap::Iface, buf: &[u8]) -> io::Result<usize> {
+        if !matches!(self.state, State::Estab | State::CloseWait) {
+            return Ok(0);
+        }
+        let n = (self.send_allowance() as usize)
+            .min(self.cong.mss as usize)
+            .min(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        self.write(nic, &buf[..n], vec![])
+    }
+
+    /// Whether the peer has finished sending: its FIN has been folded into
+    /// `recv.nxt`, so no more bytes will ever show up in `recv()`.
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Drop any segments fully covered by an incoming ACK and, per Karn's
+    /// algorithm, take an RTT sample from the oldest one that was never
+    /// retransmitted.
+    fn ack_segments(&mut self, ackn: u32) {
+        let mut sampled = false;
+        while let Some(seg) = self.unacked.front() {
+            let end = seg.seq.wrapping_add(seg.len);
+            if !seq_leq(end, ackn) {
+                break;
+            }
+            let seg = self.unacked.pop_front().expect("just peeked");
+            if !sampled && !seg.retransmitted {
+                self.timers.sample(seg.sent_at.elapsed());
+                sampled = true;
+            }
+        }
+    }
+
+    /// Fold a just-arrived segment into the receive ring buffer, even if
+    /// it's ahead of `recv.nxt`, then advance `recv.nxt` by however much of
+    /// a contiguous prefix that completed.
+    fn receive(&mut self, seqn: u32, data: &[u8], syn: bool, fin: bool) {
+        if !data.is_empty() {
+            let mut seg_seq = seqn;
+            let mut seg_data = data;
+
+            // trim off any leading bytes we've already delivered to the app
+            if (self.read_seq.wrapping_sub(seg_seq) as i32) > 0 {
+                let skip = self.read_seq.wrapping_sub(seg_seq) as usize;
+                if skip >= seg_data.len() {
+                    seg_data = &[];
+                } else {
+                    seg_seq = self.read_seq;
+                    seg_data = &seg_data[skip..];
+                }
+            }
+
+            if !seg_data.is_empty() {
+                let offset = seg_seq.wrapping_sub(self.read_seq) as usize;
+                if self.incoming.len() < offset + seg_data.len() {
+                    self.incoming.resize(offset + seg_data.len(), 0);
+                }
+                for (i, &b) in seg_data.iter().enumerate() {
+                    self.incoming[offset + i] = b;
+                }
+                // bytes before `recv.nxt` are already accounted for, so only
+                // record the part from `recv.nxt` on; a range that started
+                // below it would never line up with `pop_contiguous`
+                let seg_end = seg_seq.wrapping_add(seg_data.len() as u32);
+                let asm_start = if seq_leq(seg_seq, self.recv.nxt) { self.recv.nxt } else { seg_seq };
+                if !seq_leq(seg_end, asm_start) {
+                    self.assembler.insert(asm_start, seg_end);
+                }
+            }
+        }
+
+        if syn || fin {
+            // SYN/FIN carry no payload but each consume one sequence number;
+            // track that through the same assembler so a FIN that arrives
+            // right after a gap fills in correctly once the gap closes.
+            let ctrl_seq = seqn.wrapping_add(data.len() as u32);
+            self.assembler.insert(ctrl_seq, ctrl_seq.wrapping_add(1));
+        }
+
+        while let Some(end) = self.assembler.pop_contiguous(self.recv.nxt) {
+            self.recv.nxt = end;
+        }
+    }
+
+    /// Copy as many in-order, not-yet-read bytes as fit into `buf`. Returns
+    /// `Ok(0)` if nothing is available yet.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let readable = self.recv.nxt.wrapping_sub(self.read_seq) as usize;
+        let n = buf.len().min(readable).min(self.incoming.len());
+        for (i, b) in self.incoming.drain(..n).enumerate() {
+            buf[i] = b;
+        }
+        self.read_seq = self.read_seq.wrapping_add(n as u32);
+        Ok(n)
+    }
+
     fn send_reset(
         &mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
         self.tcp.rst = true;
@@ -206,18 +875,102 @@ impl Connection {
         //    to be received, and the connection remains in the same state.
         self.tcp.sequence_number = 0;
         self.tcp.acknowledgment_number = 0;
-        self.write(nic, &[])?;
+        self.write(nic, &[], vec![])?;
         Ok(())
     }
 
 
+    /// Handle an incoming segment while in SYN-SENT (RFC 793 S3.9). Returns
+    /// `true` once `main` should drop this connection from its table, the
+    /// same "remove me" convention `on_packet` uses elsewhere.
+    fn on_synsent_segment(
+        &mut self,
+        nic: &mut tun_tap::Iface,
+        tcph: &etherparse::TcpHeaderSlice,
+        _data: &[u8],
+    ) -> io::Result<bool> {
+        let ackn = tcph.acknowledgment_number();
+        let ack_ok = tcph.ack()
+            && seq_leq(self.send.iss.wrapping_add(1), ackn)
+            && seq_leq(ackn, self.send.nxt);
+
+        if tcph.ack() && !ack_ok {
+            if !tcph.rst() {
+                self.send_reset(nic)?;
+            }
+            return Ok(false);
+        }
+
+        if tcph.rst() {
+            // an acceptable ACK plus RST means the peer refused the
+            // connection -- tear it down so `main` stops retransmitting our
+            // SYN at a port that's never going to answer. A RST without an
+            // ACK is just dropped.
+            return Ok(ack_ok);
+        }
+
+        if !tcph.syn() {
+            // no SYN and no usable RST -- nothing to do with this segment yet
+            return Ok(false);
+        }
+
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.read_seq = self.recv.nxt;
+
+        // window scaling is only used if *both* sides offer it
+        let opts = parse_options(tcph.options());
+        let peer_mss = opts.mss.map(u32::from).unwrap_or(DEFAULT_MSS);
+        if let Some(wscale) = opts.wscale {
+            self.send_wnd_scale = wscale;
+            self.recv_wnd_scale = OUR_WND_SCALE;
+            // scaling is agreed on, so advertise our window scaled from now on
+            self.tcp.window_size = (OUR_RECV_WND >> self.recv_wnd_scale).min(u16::MAX as u32) as u16;
+        }
+        // RFC 1323 S2.3: the window field on the segment that carries the
+        // Window Scale option itself is unscaled -- scaling only applies
+        // from the next segment on
+        self.send.wnd = tcph.window_size() as u32;
+        self.send.wl1 = tcph.sequence_number();
+        self.send.wl2 = ackn;
+        self.cong = Congestion::new(peer_mss.min(OUR_MSS as u32));
+
+        if tcph.ack() {
+            // SYN+ACK: our SYN has been acked, the handshake is done
+            self.send.una = ackn;
+            self.ack_segments(ackn);
+            self.tcp.ack = true;
+            self.write(nic, &[], vec![])?;
+            self.state = State::Estab;
+        } else {
+            // simultaneous open: both sides sent a bare SYN. Ack theirs and
+            // fall back to the passive side of the handshake.
+            self.tcp.syn = true;
+            self.tcp.ack = true;
+            let opts = vec![
+                etherparse::TcpOptionElement::MaximumSegmentSize(OUR_MSS),
+                etherparse::TcpOptionElement::WindowScale(OUR_WND_SCALE),
+            ];
+            self.write(nic, &[], opts)?;
+            self.state = State::SynRcvd;
+        }
+        Ok(false)
+    }
+
     pub fn on_packet<'a>(
         &mut self,
         nic: &mut tun_tap::Iface,
         iph: etherparse::Ipv4HeaderSlice::<'a>, 
         tcph: etherparse::TcpHeaderSlice::<'a>, 
         data: &'a [u8]
-    ) -> io::Result<()> {
+    ) -> io::Result<bool> {
+        // SYN-SENT has its own segment-processing rules (RFC 793 S3.9) --
+        // until our SYN is acked we have no receive sequence space yet, so
+        // the generic acceptance checks below don't apply.
+        if let State::SynSent = self.state {
+            return self.on_synsent_segment(nic, &tcph, data);
+        }
+
         //
         // valid segment check
         // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
@@ -232,7 +985,7 @@ impl Connection {
         if tcph.syn() {
             slen += 1;
         };
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd);
         let okay = if slen == 0 {
             //separate rules for acceptance apply if segment is of zero length
             if self.recv.wnd == 0 {
@@ -258,11 +1011,15 @@ impl Connection {
         };
 
         if !okay {
-            self.write(nic, &[])?;
-            return Ok(());
+            self.write(nic, &[], vec![])?;
+            return Ok(false);
         }
 
-        self.recv.nxt = seqn.wrapping_add(slen);
+        self.receive(seqn, data, tcph.syn(), tcph.fin());
+        // whether this segment carried anything worth acking -- including a
+        // pure retransmit of data we already have, so the peer learns we
+        // got it instead of retransmitting it forever
+        let has_payload = slen > 0;
 
         //
         // Check that seq. numbers are valid (RFC 793 S3.3)
@@ -284,46 +1041,129 @@ impl Connection {
         }
         
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                return Ok(());
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            // RFC 793 S3.3: update SND.WND from this segment if it's a
+            // "newer" window update than the last one we applied -- a later
+            // SEG.SEQ, or the same SEG.SEQ acking newer data -- so a
+            // reordered segment can't make the window lurch backwards.
+            // Unlike the handshake segment (RFC 1323 S2.3), every segment
+            // from here on has its window field scaled by `send_wnd_scale`.
+            if seq_leq(self.send.wl1, seqn) && (self.send.wl1 != seqn || seq_leq(self.send.wl2, ackn)) {
+                self.send.wnd = (tcph.window_size() as u32) << self.send_wnd_scale;
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
             }
-            self.send.una = ackn;
-            // TODO: a lot, later
-            assert!(data.is_empty());
-            // let's terminate the connection
-            // TODO: needs to be stored in the retransmission queue
-            if let State::Estab = self.state {
-                self.tcp.fin = true;
-                self.write(nic, &[])?;
-                self.state = State::FinWait1;
+
+            // This block only ever updates our own send-side bookkeeping; it
+            // must never return out of `on_packet` early, since a FIN in the
+            // very same segment still has to reach the close-state handling
+            // further down regardless of what its ACK field looks like here.
+            if ackn == self.send.una && self.send.una != self.send.nxt && data.is_empty() && !tcph.fin() {
+                // duplicate ACK: no new data acked while we still have
+                // unacked data outstanding
+                if self.cong.on_duplicate_ack(self.flightsize()) {
+                    self.cong.recover = Some(self.send.nxt);
+                    self.resend_oldest(nic)?;
+                }
+            } else if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                self.send.una = ackn;
+                self.ack_segments(ackn);
+
+                match self.cong.recover {
+                    Some(recover) if seq_leq(recover, ackn) => self.cong.exit_recovery(),
+                    Some(_) => {
+                        // NewReno partial ACK: still recovering, but this ACK
+                        // covered some new data -- retransmit the next hole
+                        // without leaving fast recovery
+                        self.resend_oldest(nic)?;
+                    }
+                    None => self.cong.on_new_ack(),
+                }
             }
         }
-        
-        
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // our FIN has been ACK'd
-                self.state = State::FinWait2;
+
+        // Our own FIN (sent from `Estab`/`CloseWait` via `close()`)
+        // getting ACK'd advances the active-close and simultaneous-close
+        // paths. Compare against the FIN's recorded sequence number rather
+        // than assuming anything about `send.iss` -- that assumption breaks
+        // the moment the ISN isn't a small fixed constant.
+        let our_fin_acked = self
+            .fin_seq
+            .map_or(false, |fin_seq| seq_leq(fin_seq.wrapping_add(1), self.send.una));
+        if let State::FinWait1 | State::Closing | State::LastAck = self.state {
+            if our_fin_acked {
+                match self.state {
+                    State::FinWait1 => self.state = State::FinWait2,
+                    State::Closing => {
+                        self.time_wait_deadline = Some(time::Instant::now() + TIME_WAIT_DURATION);
+                        self.state = State::TimeWait;
+                    }
+                    State::LastAck => return Ok(true), // fully closed, remove us
+                    _ => unreachable!(),
+                }
             }
         }
 
+        let mut acked = false;
         if tcph.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    // we're done with the connection
-                    self.write(nic, &[])?;
-                    self.state = State::TimeWait;
-                },
-                _ => unreachable!(),
+            let peer_fin_seq = seqn.wrapping_add(data.len() as u32);
+            if seq_leq(peer_fin_seq.wrapping_add(1), self.recv.nxt) {
+                // the FIN is now contiguous with everything we'd already
+                // assembled, so the peer is really done sending
+                match self.state {
+                    State::Estab => {
+                        self.eof = true;
+                        self.write(nic, &[], vec![])?;
+                        self.state = State::CloseWait;
+                    }
+                    State::FinWait1 => {
+                        // simultaneous close: both sides sent FIN before
+                        // seeing the other's
+                        self.eof = true;
+                        self.write(nic, &[], vec![])?;
+                        self.state = State::Closing;
+                    }
+                    State::FinWait2 => {
+                        self.eof = true;
+                        self.write(nic, &[], vec![])?;
+                        self.time_wait_deadline = Some(time::Instant::now() + TIME_WAIT_DURATION);
+                        self.state = State::TimeWait;
+                    }
+                    _ => {
+                        // already past the point of caring (e.g. a
+                        // retransmitted FIN) -- just re-ack it
+                        self.write(nic, &[], vec![])?;
+                    }
+                }
+                acked = true;
             }
         }
 
+        // A FIN above already carries an ack for everything up to and
+        // including it. Otherwise, if this segment carried any data --
+        // in-order, the missing piece of a reordered one, or even a pure
+        // retransmit of bytes we already have -- the sender needs to hear
+        // back, or it'll just sit there retransmitting until it times out.
+        if has_payload && !acked {
+            self.write(nic, &[], vec![])?;
+        }
 
-        Ok(())
+        Ok(false)
     }
 }
 
+/// Sequence-number comparison that's correct across wraparound: is `a` at or
+/// before `b`, treating the space as a 32-bit ring?
+fn seq_leq(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) >= 0
+}
+
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     use std::cmp::{Ordering};
     match start.cmp(&x) {
@@ -331,7 +1171,7 @@ fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
         Ordering::Less => {
             // check is violated iff end is b/w start and x
             if end >= start && end <= x {
-                return false; 
+                return false;
             }
         },
         Ordering::Greater => {
@@ -345,3 +1185,123 @@ fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_leq_handles_wraparound() {
+        assert!(seq_leq(5, 5));
+        assert!(seq_leq(5, 6));
+        assert!(!seq_leq(6, 5));
+        // u32::MAX is "before" 0 once we wrap around
+        assert!(seq_leq(u32::MAX, 0));
+        assert!(!seq_leq(0, u32::MAX));
+    }
+
+    #[test]
+    fn is_between_wrapped_rejects_the_endpoints() {
+        // `start == x` is always rejected, regardless of `end`
+        assert!(!is_between_wrapped(10, 10, 20));
+        // and `x == end` is rejected too: the range is open at both ends
+        assert!(!is_between_wrapped(10, 20, 20));
+    }
+
+    #[test]
+    fn is_between_wrapped_accepts_the_ordinary_case() {
+        assert!(is_between_wrapped(10, 15, 20));
+        assert!(!is_between_wrapped(10, 25, 20));
+    }
+
+    #[test]
+    fn is_between_wrapped_handles_a_window_that_wraps_the_u32_boundary() {
+        // window starts near u32::MAX and wraps around through 0
+        assert!(is_between_wrapped(u32::MAX - 5, 2, 10));
+        assert!(!is_between_wrapped(u32::MAX - 5, 20, 10));
+    }
+
+    #[test]
+    fn assembler_delivers_in_order_bytes_immediately() {
+        let mut a = Assembler::default();
+        a.insert(0, 10);
+        assert_eq!(a.pop_contiguous(0), Some(10));
+        // already popped -- asking again for the same start finds nothing
+        assert_eq!(a.pop_contiguous(0), None);
+    }
+
+    #[test]
+    fn assembler_holds_out_of_order_bytes_until_the_gap_closes() {
+        let mut a = Assembler::default();
+        a.insert(10, 20); // arrives first, but there's a gap before it
+        assert_eq!(a.pop_contiguous(0), None);
+        a.insert(0, 10); // fills the gap
+        assert_eq!(a.pop_contiguous(0), Some(20));
+    }
+
+    #[test]
+    fn assembler_merges_overlapping_and_touching_ranges() {
+        let mut a = Assembler::default();
+        a.insert(0, 5);
+        a.insert(3, 8); // overlaps the first range
+        a.insert(8, 10); // merely touches the merged range
+        assert_eq!(a.pop_contiguous(0), Some(10));
+    }
+
+    #[test]
+    fn timers_rto_starts_at_the_floor_and_tracks_rtt_samples() {
+        let mut t = Timers::default();
+        assert_eq!(t.rto, RTO_MIN);
+
+        t.sample(time::Duration::from_millis(100));
+        assert!(t.srtt.is_some());
+        // a single sample pushes the RTO above the bare RTT (RTTVAR starts
+        // at half the sample, so RTO = SRTT + 4*RTTVAR is several times it)
+        assert!(t.rto > time::Duration::from_millis(100));
+        assert!(t.rto <= RTO_MAX);
+    }
+
+    #[test]
+    fn timers_backoff_doubles_and_clamps_to_rto_max() {
+        let mut t = Timers {
+            rto: RTO_MAX - time::Duration::from_secs(1),
+            ..Default::default()
+        };
+        t.backoff();
+        assert_eq!(t.rto, RTO_MAX);
+    }
+
+    #[test]
+    fn congestion_new_clamps_a_zero_mss() {
+        // a peer-advertised MSS of 0 must never produce a zero cwnd, or the
+        // congestion-avoidance division in `on_new_ack` divides by zero
+        let cong = Congestion::new(0);
+        assert_eq!(cong.mss, 1);
+        assert_eq!(cong.window(), 3);
+    }
+
+    #[test]
+    fn congestion_slow_start_grows_by_one_mss_per_ack() {
+        let mut cong = Congestion::new(500);
+        let initial = cong.window();
+        cong.on_new_ack();
+        assert_eq!(cong.window(), initial + 500);
+    }
+
+    #[test]
+    fn congestion_third_duplicate_ack_triggers_fast_retransmit() {
+        let mut cong = Congestion::new(500);
+        assert!(!cong.on_duplicate_ack(4000));
+        assert!(!cong.on_duplicate_ack(4000));
+        assert!(cong.on_duplicate_ack(4000));
+        assert_eq!(cong.ssthresh, 2000); // flightsize/2 dominates here (> 2*mss)
+    }
+
+    #[test]
+    fn congestion_rto_resets_to_one_mss_and_halves_ssthresh() {
+        let mut cong = Congestion::new(500);
+        cong.on_rto(4000);
+        assert_eq!(cong.window(), 500);
+        assert_eq!(cong.ssthresh, 2000); // flightsize/2 dominates here (> 2*mss)
+    }
+}